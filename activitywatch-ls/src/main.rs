@@ -1,19 +1,24 @@
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::{collections::HashMap, error::Error, sync::Arc};
 
 use arc_swap::ArcSwap;
 use aw_client_rust::AwClient;
-use chrono::{DateTime, Local, TimeDelta};
+use chrono::{DateTime, Local, TimeDelta, Utc};
 use clap::{value_parser, Arg, Command};
-use lsp_types::notification::{DidChangeTextDocument, DidOpenTextDocument};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use lsp_types::{
-    request::GotoDefinition, GotoDefinitionResponse, InitializeParams, ServerCapabilities,
+    InitializeParams, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind,
 };
-use lsp_types::{OneOf, TextDocumentSyncCapability, TextDocumentSyncKind};
 
-use lsp_server::{Connection, ExtractError, Message, Notification, Request, RequestId, Response};
+use lsp_server::{Connection, ExtractError, Message, Notification};
 
 #[derive(Default, Debug)]
 struct Event {
@@ -28,26 +33,40 @@ struct CurrentFile {
     timestamp: DateTime<Local>,
 }
 
+/// A heartbeat that could not be delivered, persisted so it can be re-sent
+/// once the ActivityWatch server comes back.
+#[derive(Serialize, Deserialize)]
+struct SpooledHeartbeat {
+    bucket_id: String,
+    event: aw_client_rust::Event,
+    pulsetime: f64,
+}
+
 struct ActivityWatchLanguageServer {
-    client: Connection,
     current_file: Mutex<CurrentFile>,
     aw_client: AwClient,
     bucket_id: String,
     file_languages: Mutex<HashMap<String, String>>,
     project: ArcSwap<Option<String>>,
+    /// Seconds within which consecutive edits are coalesced by the server.
+    pulsetime: f64,
+    /// Minimum time between heartbeats for the same unchanged file.
+    heartbeat_interval: TimeDelta,
+    /// Cache file holding heartbeats that failed to send, or `None` when no
+    /// data directory could be resolved.
+    spool_path: Option<PathBuf>,
 }
 
 impl ActivityWatchLanguageServer {
-    async fn send(&self, event: Event) {
+    fn send(&self, event: Event) {
         // if isWrite is false, and file has not changed since last heartbeat,
-        // and it has been less than 1 second since the last heartbeat do nothing
-        const INTERVAL: TimeDelta = TimeDelta::seconds(1);
-
+        // and it has been less than `heartbeat_interval` since the last
+        // heartbeat do nothing
         let mut current_file = self.current_file.lock().unwrap();
         let now = Local::now();
 
         if event.uri == current_file.uri
-            && now - current_file.timestamp < INTERVAL
+            && now - current_file.timestamp < self.heartbeat_interval
             && event.is_write
         {
             return;
@@ -72,83 +91,181 @@ impl ActivityWatchLanguageServer {
         // https://github.com/ActivityWatch/aw-watcher-vscode/blob/36093d4ac133f04363f144bdfefa4523f8e8f25f/src/extension.ts#L139
         let aw_event = aw_client_rust::Event::new(now.to_utc(), TimeDelta::zero(), data);
 
-        const PULSETIME: f64 = 60_f64;
-        if let Err(e) = self
+        match self
             .aw_client
-            .heartbeat(&self.bucket_id, &aw_event, PULSETIME)
+            .heartbeat(&self.bucket_id, &aw_event, self.pulsetime)
         {
-            eprintln!("Received error trying to send a heartbeat to the server: {e:?}");
+            Ok(()) => self.drain_spool(),
+            Err(e) => {
+                eprintln!("Received error trying to send a heartbeat to the server: {e:?}");
+                self.spool(&aw_event);
+            }
         }
 
         current_file.uri = event.uri;
         current_file.timestamp = now;
     }
-}
 
-//impl LanguageServer for ActivityWatchLanguageServer {
-//    async fn initialize(&self, params: InitializeParams) -> jsonrpc::Result<InitializeResult> {
-//        if let Some(folders) = params.workspace_folders {
-//            if let Some(folder) = folders.get(0) {
-//                let path = folder.uri.path().to_string();
-//                self.project.swap(Arc::new(Some(path)));
-//            }
-//        }
-//        Ok(InitializeResult {
-//            server_info: Some(ServerInfo {
-//                name: env!("CARGO_PKG_NAME").to_string(),
-//                version: Some(env!("CARGO_PKG_VERSION").to_string()),
-//            }),
-//            capabilities: ServerCapabilities {
-//                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-//                    TextDocumentSyncKind::INCREMENTAL,
-//                )),
-//                ..Default::default()
-//            },
-//        })
-//    }
-//
-//    // Note that zed (and probably other editors) do this not when a file is in the foreground
-//    // but as soon as it is opened, which makes sense but is annoying for us.
-//    // Reporting the time between when a file is foregrounded and a change is made would require
-//    // us to look at a whole bunch of other events or something bleh.
-//    async fn did_open(&self, params: DidOpenTextDocumentParams) {
-//        let event = Event {
-//            uri: params.text_document.uri[url::Position::BeforeUsername..].to_string(),
-//            is_write: false,
-//            language: Some(params.text_document.language_id.clone()),
-//        };
-//
-//        // This is a minor memory leak and ideally we'd look for close events
-//        // to remove entries
-//        self.file_languages
-//            .lock()
-//            .await
-//            .insert(event.uri.clone(), params.text_document.language_id);
-//
-//        // TODO: keep tabs on whether or not to do this
-//        // self.send(event).await;
-//    }
-//
-//    async fn did_change(&self, params: DidChangeTextDocumentParams) {
-//        let event = Event {
-//            uri: params.text_document.uri[url::Position::BeforeUsername..].to_string(),
-//            is_write: false,
-//            language: None,
-//        };
-//
-//        self.send(event).await;
-//    }
-//
-//    async fn did_save(&self, params: DidSaveTextDocumentParams) {
-//        let event = Event {
-//            uri: params.text_document.uri[url::Position::BeforeUsername..].to_string(),
-//            is_write: true,
-//            language: None,
-//        };
-//
-//        self.send(event).await;
-//    }
-//}
+    /// Maximum number of spooled heartbeats retained on disk.
+    const MAX_SPOOL_LINES: usize = 10_000;
+    /// Maximum age of a spooled heartbeat before it is dropped.
+    const MAX_SPOOL_AGE: TimeDelta = TimeDelta::days(7);
+
+    /// Append a failed heartbeat to the on-disk spool as newline-delimited
+    /// JSON so it survives restarts and can be re-sent later.
+    fn spool(&self, event: &aw_client_rust::Event) {
+        let Some(path) = &self.spool_path else {
+            return;
+        };
+
+        let record = SpooledHeartbeat {
+            bucket_id: self.bucket_id.clone(),
+            event: event.clone(),
+            pulsetime: self.pulsetime,
+        };
+        let Ok(mut line) = serde_json::to_string(&record) else {
+            return;
+        };
+        line.push('\n');
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).ok();
+        }
+
+        let appended = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(line.as_bytes()));
+        if let Err(e) = appended {
+            eprintln!("failed to spool heartbeat to {}: {e:?}", path.display());
+        }
+
+        // Enforce the bound on append too, so the file can't grow without
+        // limit during an outage before the next successful drain.
+        self.trim_spool();
+    }
+
+    /// Parse spooled records from the spool file's contents, dropping malformed
+    /// and aged entries, sorting by timestamp and
+    /// capping the total to the most recent [`Self::MAX_SPOOL_LINES`].
+    fn load_spool(&self, contents: &str) -> Vec<SpooledHeartbeat> {
+        let cutoff = Utc::now() - Self::MAX_SPOOL_AGE;
+        let mut records: Vec<SpooledHeartbeat> = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str::<SpooledHeartbeat>(line).ok())
+            .filter(|record| record.event.timestamp >= cutoff)
+            .collect();
+        records.sort_by_key(|record| record.event.timestamp);
+        if records.len() > Self::MAX_SPOOL_LINES {
+            let overflow = records.len() - Self::MAX_SPOOL_LINES;
+            records.drain(0..overflow);
+        }
+        records
+    }
+
+    /// Rewrite the spool file with the given records in order.
+    fn write_spool(&self, path: &Path, records: &[SpooledHeartbeat]) {
+        if records.is_empty() {
+            fs::remove_file(path).ok();
+            return;
+        }
+        let mut data = String::new();
+        for record in records {
+            if let Ok(line) = serde_json::to_string(record) {
+                data.push_str(&line);
+                data.push('\n');
+            }
+        }
+        fs::write(path, data).ok();
+    }
+
+    /// Drop aged and overflowing records from the spool without sending.
+    fn trim_spool(&self) {
+        let Some(path) = &self.spool_path else {
+            return;
+        };
+        let Ok(contents) = fs::read_to_string(path) else {
+            return;
+        };
+        let records = self.load_spool(&contents);
+        self.write_spool(path, &records);
+    }
+
+    /// Re-send any spooled heartbeats in timestamp order and clear the cache.
+    ///
+    /// Records older than [`Self::MAX_SPOOL_AGE`] are dropped, and at most
+    /// [`Self::MAX_SPOOL_LINES`] of the most recent records are kept so the
+    /// file cannot grow without bound. If any record fails to send again, the
+    /// remaining backlog is written back for a future attempt.
+    fn drain_spool(&self) {
+        let Some(path) = &self.spool_path else {
+            return;
+        };
+        let Ok(contents) = fs::read_to_string(path) else {
+            return;
+        };
+
+        let records = self.load_spool(&contents);
+
+        let mut backlog = Vec::new();
+        for record in records {
+            if !backlog.is_empty() {
+                // A send already failed this pass; keep the rest for later.
+                backlog.push(record);
+                continue;
+            }
+            if self
+                .aw_client
+                .heartbeat(&record.bucket_id, &record.event, record.pulsetime)
+                .is_err()
+            {
+                backlog.push(record);
+            }
+        }
+
+        self.write_spool(path, &backlog);
+    }
+
+    // Note that zed (and probably other editors) do this not when a file is in the foreground
+    // but as soon as it is opened, which makes sense but is annoying for us.
+    // Reporting the time between when a file is foregrounded and a change is made would require
+    // us to look at a whole bunch of other events or something bleh.
+    fn did_open(&self, params: lsp_types::DidOpenTextDocumentParams) {
+        let uri = params.text_document.uri[url::Position::BeforeUsername..].to_string();
+
+        // This is a minor memory leak and ideally we'd look for close events
+        // to remove entries
+        self.file_languages
+            .lock()
+            .unwrap()
+            .insert(uri, params.text_document.language_id);
+
+        // TODO: keep tabs on whether or not to do this
+        // self.send(event);
+    }
+
+    fn did_change(&self, params: lsp_types::DidChangeTextDocumentParams) {
+        let event = Event {
+            uri: params.text_document.uri[url::Position::BeforeUsername..].to_string(),
+            is_write: false,
+            language: None,
+        };
+
+        self.send(event);
+    }
+
+    fn did_save(&self, params: lsp_types::DidSaveTextDocumentParams) {
+        let event = Event {
+            uri: params.text_document.uri[url::Position::BeforeUsername..].to_string(),
+            is_write: true,
+            language: None,
+        };
+
+        self.send(event);
+    }
+}
 
 fn main() {
     let matches = Command::new("activitywatch_ls")
@@ -172,12 +289,30 @@ fn main() {
                 .required(false)
                 .default_value("5600"),
         )
+        .arg(
+            Arg::new("pulsetime")
+                .value_parser(value_parser!(f64))
+                .long("pulsetime")
+                .help("Seconds within which consecutive edits are coalesced into one event")
+                .required(false)
+                .default_value("60"),
+        )
+        .arg(
+            Arg::new("heartbeat-interval")
+                .value_parser(value_parser!(u64))
+                .long("heartbeat-interval")
+                .help("Minimum seconds between heartbeats for the same unchanged file")
+                .required(false)
+                .default_value("1"),
+        )
         .get_matches();
 
     // Note that AwClient does not support https
     // TODO: this sucks and i hate the alternatives too lol
     let host: &String = matches.get_one("host").unwrap();
     let port: &u16 = matches.get_one("port").unwrap();
+    let pulsetime: f64 = *matches.get_one("pulsetime").unwrap();
+    let heartbeat_interval = TimeDelta::seconds(*matches.get_one::<u64>("heartbeat-interval").unwrap() as i64);
 
     const CLIENT_NAME: &str = "aw-watcher-zed";
     let aw_client = match AwClient::new(host, *port, CLIENT_NAME) {
@@ -202,7 +337,7 @@ fn main() {
     let (connection, io_threads) = Connection::stdio();
 
     // Run the server and wait for the two threads to end (typically by trigger LSP Exit event).
-    let server_capabilities = serde_json::to_value(&ServerCapabilities {
+    let server_capabilities = serde_json::to_value(ServerCapabilities {
         text_document_sync: Some(TextDocumentSyncCapability::Kind(
             TextDocumentSyncKind::INCREMENTAL,
         )),
@@ -210,13 +345,6 @@ fn main() {
     })
     .unwrap();
 
-    //if let Some(folders) = params.workspace_folders {
-    //    if let Some(folder) = folders.get(0) {
-    //        let path = folder.uri.path().to_string();
-    //        self.project.swap(Arc::new(Some(path)));
-    //    }
-    //}
-    //.unwrap();
     let initialization_params = match connection.initialize(server_capabilities) {
         Ok(it) => it,
         Err(e) => {
@@ -226,7 +354,17 @@ fn main() {
             return; //TODO
         }
     };
-    main_loop(connection, initialization_params).unwrap();
+
+    if let Err(e) = main_loop(
+        connection,
+        aw_client,
+        bucket_id,
+        pulsetime,
+        heartbeat_interval,
+        initialization_params,
+    ) {
+        eprintln!("main loop exited with error: {e:?}");
+    }
     io_threads.join().unwrap();
 
     // Shut down gracefully.
@@ -235,9 +373,35 @@ fn main() {
 
 fn main_loop(
     connection: Connection,
+    aw_client: AwClient,
+    bucket_id: String,
+    pulsetime: f64,
+    heartbeat_interval: TimeDelta,
     params: serde_json::Value,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
-    let _params: InitializeParams = serde_json::from_value(params).unwrap();
+    let params: InitializeParams = serde_json::from_value(params).unwrap();
+
+    let project = params
+        .workspace_folders
+        .as_ref()
+        .and_then(|folders| folders.first())
+        .and_then(|folder| folder.uri.to_file_path().ok())
+        .and_then(|path| project_name(&path));
+
+    let server = ActivityWatchLanguageServer {
+        current_file: Mutex::new(CurrentFile {
+            uri: String::new(),
+            timestamp: Local::now(),
+        }),
+        aw_client,
+        bucket_id,
+        file_languages: Mutex::new(HashMap::new()),
+        project: ArcSwap::new(Arc::new(project)),
+        pulsetime,
+        heartbeat_interval,
+        spool_path: spool_path(),
+    };
+
     eprintln!("starting example main loop");
     for msg in &connection.receiver {
         eprintln!("got msg: {msg:?}");
@@ -253,35 +417,86 @@ fn main_loop(
                 eprintln!("got response: {resp:?}");
             }
             Message::Notification(not) => {
+                let not = match cast_notification::<DidOpenTextDocument>(not) {
+                    Ok(params) => {
+                        server.did_open(params);
+                        continue;
+                    }
+                    Err(err @ ExtractError::JsonError { .. }) => {
+                        eprintln!("failed to parse didOpen notification: {err:?}");
+                        continue;
+                    }
+                    Err(ExtractError::MethodMismatch(not)) => not,
+                };
+                let not = match cast_notification::<DidChangeTextDocument>(not) {
+                    Ok(params) => {
+                        server.did_change(params);
+                        continue;
+                    }
+                    Err(err @ ExtractError::JsonError { .. }) => {
+                        eprintln!("failed to parse didChange notification: {err:?}");
+                        continue;
+                    }
+                    Err(ExtractError::MethodMismatch(not)) => not,
+                };
+                let not = match cast_notification::<DidSaveTextDocument>(not) {
+                    Ok(params) => {
+                        server.did_save(params);
+                        continue;
+                    }
+                    Err(err @ ExtractError::JsonError { .. }) => {
+                        eprintln!("failed to parse didSave notification: {err:?}");
+                        continue;
+                    }
+                    Err(ExtractError::MethodMismatch(not)) => not,
+                };
                 eprintln!("got notification: {not:?}");
-                //match cast_notification::<DidOpenTextDocument>(not) {
-                //    Ok((id, params)) => {
-                //        eprintln!("got DidOpenTextDocument notification #{id}: {params:?}");
-                //        continue;
-                //    }
-                //    Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-                //    Err(ExtractError::MethodMismatch(req)) => req,
-                //};
             }
         }
     }
     Ok(())
 }
 
-fn cast<R>(req: Request) -> Result<(RequestId, R::Params), ExtractError<Request>>
-where
-    R: lsp_types::request::Request,
-    R::Params: serde::de::DeserializeOwned,
-{
-    req.extract(R::METHOD)
+/// Resolve the newline-delimited JSON cache file used to spool heartbeats
+/// that could not be delivered: under `%APPDATA%` on Windows, and under
+/// `$XDG_DATA_HOME` (or `~/.local/share`) on every other platform.
+fn spool_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+    }?;
+
+    Some(base.join("activitywatch").join("aw-watcher-zed").join("heartbeats.ndjson"))
+}
+
+/// Derive a stable, human-meaningful project label from a workspace folder.
+///
+/// Walk upward from `folder` to the nearest enclosing git repository and use
+/// that repository's directory name, falling back to the folder's own basename
+/// when the folder is not inside a repo.
+fn project_name(folder: &Path) -> Option<String> {
+    let mut dir = Some(folder);
+    while let Some(current) = dir {
+        if current.join(".git").exists() {
+            return current
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned());
+        }
+        dir = current.parent();
+    }
+
+    folder
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
 }
 
-fn cast_notification<R>(
-    req: Notification,
-) -> Result<(RequestId, R::Params), ExtractError<Notification>>
+fn cast_notification<N>(not: Notification) -> Result<N::Params, ExtractError<Notification>>
 where
-    R: lsp_types::notification::Notification,
-    R::Params: serde::de::DeserializeOwned,
+    N: lsp_types::notification::Notification,
+    N::Params: serde::de::DeserializeOwned,
 {
-    req.extract(R::METHOD)
+    not.extract(N::METHOD)
 }
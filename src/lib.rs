@@ -1,6 +1,7 @@
 use std::fs;
 
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use zed_extension_api::{
     self as zed, serde_json, settings::LspSettings, Command, LanguageServerId, Result, Worktree,
 };
@@ -9,9 +10,24 @@ use zed_extension_api::{
 struct Configuration {
     host: Option<String>,
     port: Option<u16>,
+    /// Explicit path to an `activitywatch-ls` binary, honored before the
+    /// cached binary and the GitHub download. A binary found by the
+    /// `worktree.which` probe still takes precedence over it.
+    binary_path: Option<String>,
+    /// Seconds within which consecutive edits are coalesced into one event.
+    pulsetime: Option<f64>,
+    /// Minimum seconds between heartbeats for the same unchanged file.
+    heartbeat_interval: Option<u64>,
 }
+/// A resolved language server binary together with the release version it was
+/// extracted from, so repeated worktree opens can skip re-extraction.
+struct ActivityWatchLanguageServerBinary {
+    path: String,
+    version: String,
+}
+
 struct ActivityWatchExtension {
-    cached_ls_binary_path: Option<String>,
+    cached_ls_binary: Option<ActivityWatchLanguageServerBinary>,
 }
 
 impl ActivityWatchExtension {
@@ -36,7 +52,11 @@ impl ActivityWatchExtension {
         Ok(format!("activitywatch-ls-{arch}-{os}"))
     }
 
-    fn download(&self, language_server_id: &LanguageServerId, repo: &str) -> Result<String> {
+    fn download(
+        &self,
+        language_server_id: &LanguageServerId,
+        repo: &str,
+    ) -> Result<ActivityWatchLanguageServerBinary> {
         let release = zed::latest_github_release(
             repo,
             zed::GithubReleaseOptions {
@@ -45,14 +65,38 @@ impl ActivityWatchExtension {
             },
         )?;
 
+        // If the cached binary is already at the latest version, reuse it
+        // instead of re-extracting the archive.
+        if let Some(cached) = &self.cached_ls_binary {
+            if cached.version == release.version
+                && fs::metadata(&cached.path).map_or(false, |stat| stat.is_file())
+            {
+                return Ok(ActivityWatchLanguageServerBinary {
+                    path: cached.path.clone(),
+                    version: cached.version.clone(),
+                });
+            }
+        }
+
         let target_triple = self.target_triple()?;
 
-        let asset_name = format!("{target_triple}.zip");
-        let asset = release
-            .assets
-            .iter()
-            .find(|asset| asset.name == asset_name)
-            .ok_or_else(|| format!("no asset found matching {:?}", asset_name))?;
+        // Probe for assets in priority order: gzip tarballs are the smaller
+        // download, then the zip fallback that every platform still ships.
+        // (`zed::download_file` has no xz decoder, so `.tar.xz` is not an
+        // option here.)
+        let (asset, file_type) = [
+            (format!("{target_triple}.tar.gz"), zed::DownloadedFileType::GzipTar),
+            (format!("{target_triple}.zip"), zed::DownloadedFileType::Zip),
+        ]
+        .into_iter()
+        .find_map(|(name, file_type)| {
+            release
+                .assets
+                .iter()
+                .find(|asset| asset.name == name)
+                .map(|asset| (asset, file_type))
+        })
+        .ok_or_else(|| format!("no asset found matching {target_triple}"))?;
 
         let version_dir = format!("activitywatch-ls-{}", release.version);
         let binary_path = format!("{version_dir}/activitywatch-ls");
@@ -62,12 +106,19 @@ impl ActivityWatchExtension {
                 &zed::LanguageServerInstallationStatus::Downloading,
             );
 
-            zed::download_file(
-                &asset.download_url,
-                &version_dir,
-                zed::DownloadedFileType::Zip,
-            )
-            .map_err(|err| format!("failed to download file: {err}"))?;
+            // Verify the downloaded archive against a published checksum, if
+            // any, before extracting, and refuse to complete the install on
+            // mismatch.
+            if let Err(err) = self.verify_checksum(&release, asset) {
+                zed::set_language_server_installation_status(
+                    language_server_id,
+                    &zed::LanguageServerInstallationStatus::Failed(err.clone()),
+                );
+                return Err(err);
+            }
+
+            zed::download_file(&asset.download_url, &version_dir, file_type)
+                .map_err(|err| format!("failed to download file: {err}"))?;
 
             // Delete old versions
             let entries = fs::read_dir(".")
@@ -84,13 +135,78 @@ impl ActivityWatchExtension {
 
         zed::make_file_executable(&binary_path)?;
 
-        Ok(binary_path)
+        Ok(ActivityWatchLanguageServerBinary {
+            path: binary_path,
+            version: release.version,
+        })
+    }
+
+    /// Verify the release archive against a `{asset_name}.sha256` checksum
+    /// asset when the release publishes one. A missing checksum asset is
+    /// treated as unverifiable and accepted; only a present-but-mismatching
+    /// checksum fails.
+    ///
+    /// The `.sha256` asset follows GitHub convention and covers the *archive*,
+    /// so we hash the downloaded archive bytes rather than the unpacked binary.
+    fn verify_checksum(
+        &self,
+        release: &zed::GithubRelease,
+        asset: &zed::GithubReleaseAsset,
+    ) -> Result<()> {
+        let checksum_name = format!("{}.sha256", asset.name);
+        let Some(checksum_asset) = release.assets.iter().find(|a| a.name == checksum_name) else {
+            return Ok(());
+        };
+
+        // Fetch the archive verbatim so we can hash exactly what the checksum
+        // covers, independent of extraction.
+        let archive_path = format!("{}.download", asset.name);
+        zed::download_file(
+            &asset.download_url,
+            &archive_path,
+            zed::DownloadedFileType::Uncompressed,
+        )
+        .map_err(|err| format!("failed to download archive: {err}"))?;
+
+        let checksum_path = format!("{archive_path}.sha256");
+        zed::download_file(
+            &checksum_asset.download_url,
+            &checksum_path,
+            zed::DownloadedFileType::Uncompressed,
+        )
+        .map_err(|err| format!("failed to download checksum: {err}"))?;
+
+        let checksum = fs::read_to_string(&checksum_path)
+            .map_err(|err| format!("failed to read checksum: {err}"))?;
+        // Checksum files are usually `<hex>  <filename>`; take the first field.
+        let expected = checksum
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let bytes =
+            fs::read(&archive_path).map_err(|err| format!("failed to read archive: {err}"))?;
+        let actual = format!("{:x}", Sha256::digest(&bytes));
+
+        fs::remove_file(&archive_path).ok();
+        fs::remove_file(&checksum_path).ok();
+
+        if actual != expected {
+            return Err(format!(
+                "checksum mismatch for {}: expected {expected}, got {actual}",
+                asset.name
+            ));
+        }
+
+        Ok(())
     }
 
     fn language_server_binary_path(
         &mut self,
         language_server_id: &LanguageServerId,
         worktree: &Worktree,
+        binary_path_override: Option<String>,
     ) -> Result<String, String> {
         zed::set_language_server_installation_status(
             language_server_id,
@@ -106,24 +222,32 @@ impl ActivityWatchExtension {
             return Ok(path.clone());
         }
 
-        if let Some(path) = &self.cached_ls_binary_path {
-            if fs::metadata(path).map_or(false, |stat| stat.is_file()) {
-                return Ok(path.clone());
+        if let Some(path) = binary_path_override {
+            return Ok(path);
+        }
+
+        // Offline fast path: if we already extracted a binary this session and
+        // the file is still present, reuse it without touching the network, so
+        // the LS can start even when GitHub is unreachable.
+        if let Some(cached) = &self.cached_ls_binary {
+            if fs::metadata(&cached.path).map_or(false, |stat| stat.is_file()) {
+                return Ok(cached.path.clone());
             }
         }
 
-        let binary_path = self.download(language_server_id, "sachk/aw-watcher-zed")?;
+        let binary = self.download(language_server_id, "sachk/aw-watcher-zed")?;
 
-        self.cached_ls_binary_path = Some(binary_path.clone());
+        let path = binary.path.clone();
+        self.cached_ls_binary = Some(binary);
 
-        Ok(binary_path)
+        Ok(path)
     }
 }
 
 impl zed::Extension for ActivityWatchExtension {
     fn new() -> Self {
         Self {
-            cached_ls_binary_path: None,
+            cached_ls_binary: None,
         }
     }
 
@@ -136,29 +260,41 @@ impl zed::Extension for ActivityWatchExtension {
         let lsp_settings =
             LspSettings::for_worktree(language_server_id.to_string().as_str(), worktree)?;
 
-        let args = match lsp_settings.settings {
+        let config = match lsp_settings.settings {
             Some(s) => match serde_json::from_value::<Configuration>(s) {
-                Ok(config) => {
-                    let mut args = Vec::new();
-                    if let Some(host) = config.host {
-                        args.push("--host".to_string());
-                        args.push(host);
-                    }
-                    if let Some(port) = config.port {
-                        args.push("--port".to_string());
-                        args.push(port.to_string());
-                    }
-                    args
-                }
+                Ok(config) => Some(config),
                 Err(e) => {
                     println!("error! {e:#?}");
-                    Vec::new()
+                    None
                 }
             },
-            None => Vec::new(),
+            None => None,
         };
 
-        let ls_binary_path = self.language_server_binary_path(language_server_id, worktree)?;
+        let mut args = Vec::new();
+        let mut binary_path_override = None;
+        if let Some(config) = config {
+            if let Some(host) = config.host {
+                args.push("--host".to_string());
+                args.push(host);
+            }
+            if let Some(port) = config.port {
+                args.push("--port".to_string());
+                args.push(port.to_string());
+            }
+            if let Some(pulsetime) = config.pulsetime {
+                args.push("--pulsetime".to_string());
+                args.push(pulsetime.to_string());
+            }
+            if let Some(heartbeat_interval) = config.heartbeat_interval {
+                args.push("--heartbeat-interval".to_string());
+                args.push(heartbeat_interval.to_string());
+            }
+            binary_path_override = config.binary_path;
+        }
+
+        let ls_binary_path =
+            self.language_server_binary_path(language_server_id, worktree, binary_path_override)?;
 
         Ok(Command {
             args,